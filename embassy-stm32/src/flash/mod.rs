@@ -0,0 +1,56 @@
+mod u5;
+
+pub use u5::*;
+
+/// Size, in bytes, of the smallest unit [`blocking_write`]/[`write`] can program.
+pub const WRITE_SIZE: usize = 16;
+
+/// Base address of the internal flash.
+pub const FLASH_BASE: usize = 0x0800_0000;
+
+/// Total size, in bytes, of the internal flash on this chip.
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+/// A flash bank. In a [`FlashSector`] this is the logical bank, which is
+/// mapped to the `BKER` control bit with the `SWAP_BANK` option bit taken
+/// into account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FlashBank {
+    Bank1,
+    Bank2,
+}
+
+/// An erasable sector of flash.
+#[derive(Debug, Clone, Copy)]
+pub struct FlashSector {
+    pub bank: FlashBank,
+    pub index_in_bank: u8,
+    pub start: u32,
+    pub size: u32,
+}
+
+/// Errors returned by the flash program/erase/option-byte operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The flash controller was still busy with another operation.
+    Busy,
+    /// Programming sequence error.
+    Seq,
+    /// Programming parallelism/size error.
+    Size,
+    /// `start_address` is not aligned to [`WRITE_SIZE`].
+    Unaligned,
+    /// The target is write-protected, or RDP forbids the requested change.
+    Protected,
+    /// The controller reported a programming error.
+    Prog,
+    /// A single-bit ECC error was detected and silently corrected by hardware.
+    EccCorrected,
+    /// An uncorrectable double-bit ECC error was detected.
+    EccUncorrected,
+    /// A post-write read-back did not match the source buffer.
+    Verify,
+    /// `start_address` (or the requested range) falls outside this chip's flash.
+    OutOfBounds,
+}
@@ -1,10 +1,22 @@
+use core::future::poll_fn;
 use core::ptr::write_volatile;
 use core::sync::atomic::{Ordering, fence};
+use core::task::Poll;
+
+use embassy_futures::select::select;
+use embassy_sync::waitqueue::AtomicWaker;
+use embassy_time::Timer;
 
 use super::{FlashBank, FlashSector, WRITE_SIZE};
 use crate::flash::Error;
 use crate::pac;
 
+/// How often `wait_ready_async` re-checks `status()` on its own, in case the
+/// FLASH interrupt isn't bound and the completion wake never arrives.
+const POLL_FALLBACK_INTERVAL_MS: u64 = 1;
+
+static WAKER: AtomicWaker = AtomicWaker::new();
+
 pub(crate) fn lock() {
     #[cfg(feature = "trustzone-secure")]
     pac::FLASH.seccr().modify(|w| w.set_lock(true));
@@ -28,6 +40,12 @@ pub(crate) fn unlock() {
 pub(crate) fn enable_write() {
     assert_eq!(0, WRITE_SIZE % 4);
 
+    // ECCC/ECCD are latched by *any* flash access, not just ours, so a stray
+    // hit from code fetched between the previous operation and this one
+    // would otherwise look like it was caused by this write. Clear them
+    // before starting so `status()` only reports faults from here on.
+    clear_ecc_err();
+
     #[cfg(feature = "trustzone-secure")]
     pac::FLASH.seccr().write(|w| {
         w.set_pg(true);
@@ -45,8 +63,41 @@ pub(crate) fn disable_write() {
     pac::FLASH.nscr().write(|w| w.set_pg(false));
 }
 
+/// Bounds- and alignment-checks a `start_address..start_address + len` range
+/// before any hardware access happens, so a bad range turns into a clean
+/// error instead of a PGAERR/SIZERR fault or a silent write to the wrong
+/// location. `len` is folded in with `checked_add` so a `start_address` near
+/// `u32::MAX` fails the bounds check instead of wrapping past it.
+fn validate_range(start_address: u32, len: u32) -> Result<(), Error> {
+    if start_address % WRITE_SIZE as u32 != 0 {
+        return Err(Error::Unaligned);
+    }
+
+    let flash_end = super::FLASH_BASE as u32 + super::FLASH_SIZE as u32;
+    let end_address = start_address.checked_add(len).ok_or(Error::OutOfBounds)?;
+    if start_address < super::FLASH_BASE as u32 || end_address > flash_end {
+        return Err(Error::OutOfBounds);
+    }
+
+    Ok(())
+}
+
+/// Validates a write target: exactly [`WRITE_SIZE`] bytes starting at `start_address`.
+fn validate_address(start_address: u32) -> Result<(), Error> {
+    validate_range(start_address, WRITE_SIZE as u32)
+}
+
+/// Validates an erase target against the sector's actual size, not just its
+/// first [`WRITE_SIZE`] bytes - a sector whose `start` is in range but whose
+/// tail runs past the end of flash must still be rejected.
+fn validate_sector_address(sector: &FlashSector) -> Result<(), Error> {
+    validate_range(sector.start, sector.size)
+}
+
 pub(crate) unsafe fn write(start_address: u32, buf: &[u8; WRITE_SIZE]) -> Result<(), Error> {
-    let mut address = start_address;
+    validate_address(start_address)?;
+
+    let mut address = translate_address(start_address);
     for val in buf.chunks(4) {
         write_volatile(address as *mut u32, u32::from_le_bytes(unwrap!(val.try_into())));
         address += val.len() as u32;
@@ -63,28 +114,238 @@ pub(crate) unsafe fn blocking_write(start_address: u32, buf: &[u8; WRITE_SIZE])
     blocking_wait_ready()
 }
 
-pub(crate) fn begin_erase_sector(sector: &FlashSector) {
+/// Flash interrupt handler. Binding it lets [`write_async`]/
+/// [`erase_sector_async`] complete as soon as the operation finishes; if it
+/// isn't bound, those futures still make progress through their own
+/// periodic fallback poll (see `wait_ready_async`), just with coarser
+/// latency, so callers who only need the blocking API can ignore this
+/// entirely.
+pub(crate) unsafe fn on_interrupt() {
+    // Mask the interrupts that just fired - `wait_ready_async` re-enables
+    // them (or notices completion) on its next poll.
+    #[cfg(feature = "trustzone-secure")]
+    pac::FLASH.seccr().modify(|w| {
+        w.set_eopie(false);
+        w.set_errie(false);
+    });
+    #[cfg(not(feature = "trustzone-secure"))]
+    pac::FLASH.nscr().modify(|w| {
+        w.set_eopie(false);
+        w.set_errie(false);
+    });
+
+    WAKER.wake();
+}
+
+fn enable_completion_interrupt() {
+    #[cfg(feature = "trustzone-secure")]
+    pac::FLASH.seccr().modify(|w| {
+        w.set_eopie(true);
+        w.set_errie(true);
+    });
+    #[cfg(not(feature = "trustzone-secure"))]
+    pac::FLASH.nscr().modify(|w| {
+        w.set_eopie(true);
+        w.set_errie(true);
+    });
+}
+
+async fn wait_ready_async() -> Result<(), Error> {
+    enable_completion_interrupt();
+
+    loop {
+        let woken = poll_fn(|cx| {
+            WAKER.register(cx.waker());
+            Poll::<()>::Pending
+        });
+
+        // Race the interrupt wake against a short timeout: if the FLASH
+        // interrupt is bound we'll normally come back out almost
+        // immediately on the wake, but if it isn't wired up this still
+        // re-checks `status()` periodically instead of hanging forever.
+        let _ = select(woken, Timer::after_millis(POLL_FALLBACK_INTERVAL_MS)).await;
+
+        match status() {
+            Ok(true) => {
+                // Still busy - re-arm the interrupt and go around again,
+                // either on the next wake or the next timeout tick.
+                enable_completion_interrupt();
+                continue;
+            }
+            Ok(false) => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Like [`blocking_write`], but reads each programmed word back afterwards
+/// and compares it against `buf`, returning [`Error::Verify`] on mismatch.
+///
+/// ECC can mask a bad write that only faults later on read, so the
+/// verification read re-checks the ECC flags (via [`status`]) as well as
+/// the raw word value.
+pub(crate) unsafe fn blocking_write_verified(start_address: u32, buf: &[u8; WRITE_SIZE]) -> Result<(), Error> {
+    blocking_write(start_address, buf)?;
+    verify(start_address, buf)
+}
+
+fn verify(start_address: u32, buf: &[u8; WRITE_SIZE]) -> Result<(), Error> {
+    let mut address = translate_address(start_address);
+    for chunk in buf.chunks(4) {
+        let expected = u32::from_le_bytes(unwrap!(chunk.try_into()));
+        let actual = unsafe { core::ptr::read_volatile(address as *const u32) };
+        if actual != expected {
+            return Err(Error::Verify);
+        }
+        address += chunk.len() as u32;
+    }
+
+    // The reads above can themselves trip a (previously latent) ECC fault,
+    // so make sure nothing crept in before declaring the write good.
+    status().map(|_| ())
+}
+
+pub(crate) async unsafe fn write_async(start_address: u32, buf: &[u8; WRITE_SIZE]) -> Result<(), Error> {
+    write(start_address, buf)?;
+    wait_ready_async().await
+}
+
+pub(crate) async fn erase_sector_async(sector: &FlashSector) -> Result<(), Error> {
+    begin_erase_sector(sector)?;
+    // We discard this Result because we regenerate it in end_erase anyway.
+    let _ = wait_ready_async().await;
+    end_erase()
+}
+
+fn swap_bank_active() -> bool {
+    pac::FLASH.optr().read().swap_bank()
+}
+
+/// Resolves a logical [`FlashSector::bank`] to the `BKER` bit to select in
+/// NSCR/SECCR, taking the `SWAP_BANK` option bit into account.
+///
+/// After a dual-bank firmware update flips `SWAP_BANK`, bank 1 and bank 2
+/// trade physical places, so a logical-to-physical inversion is needed here
+/// to keep erasing (and, via [`translate_address`], writing) pointed at the
+/// currently-active physical bank.
+pub(crate) fn resolve_bker(bank: FlashBank) -> bool {
+    resolve_bker_for(bank, swap_bank_active())
+}
+
+fn resolve_bker_for(bank: FlashBank, swapped: bool) -> bool {
+    let bker = match bank {
+        FlashBank::Bank1 => false,
+        FlashBank::Bank2 => true,
+        _ => unreachable!(),
+    };
+
+    if swapped { !bker } else { bker }
+}
+
+/// Translates a logical flash address to the physical address to actually
+/// access, applying the same `SWAP_BANK` inversion as [`resolve_bker`].
+fn translate_address(address: u32) -> u32 {
+    translate_address_for(address, swap_bank_active())
+}
+
+fn translate_address_for(address: u32, swapped: bool) -> u32 {
+    if !swapped {
+        return address;
+    }
+
+    let bank_size = super::FLASH_SIZE as u32 / 2;
+    let offset = address - super::FLASH_BASE as u32;
+
+    if offset < bank_size {
+        address + bank_size
+    } else {
+        address - bank_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_bker_follows_swap_bank() {
+        assert!(!resolve_bker_for(FlashBank::Bank1, false));
+        assert!(resolve_bker_for(FlashBank::Bank2, false));
+        assert!(resolve_bker_for(FlashBank::Bank1, true));
+        assert!(!resolve_bker_for(FlashBank::Bank2, true));
+    }
+
+    #[test]
+    fn translate_address_follows_swap_bank() {
+        let bank1_addr = super::super::FLASH_BASE as u32 + 0x100;
+        let bank2_addr = bank1_addr + (super::super::FLASH_SIZE as u32 / 2);
+
+        assert_eq!(translate_address_for(bank1_addr, false), bank1_addr);
+        assert_eq!(translate_address_for(bank1_addr, true), bank2_addr);
+        assert_eq!(translate_address_for(bank2_addr, true), bank1_addr);
+    }
+
+    #[test]
+    fn validate_range_rejects_unaligned_address() {
+        let address = super::super::FLASH_BASE as u32 + 1;
+        assert_eq!(validate_range(address, WRITE_SIZE as u32), Err(Error::Unaligned));
+    }
+
+    #[test]
+    fn validate_range_accepts_last_in_bounds_word() {
+        let flash_end = super::super::FLASH_BASE as u32 + super::super::FLASH_SIZE as u32;
+        let address = flash_end - WRITE_SIZE as u32;
+        assert_eq!(validate_range(address, WRITE_SIZE as u32), Ok(()));
+    }
+
+    #[test]
+    fn validate_range_rejects_address_at_flash_end() {
+        // One WRITE_SIZE granule past the last valid address - aligned, so
+        // this exercises the bounds check rather than the alignment check.
+        let flash_end = super::super::FLASH_BASE as u32 + super::super::FLASH_SIZE as u32;
+        assert_eq!(validate_range(flash_end, WRITE_SIZE as u32), Err(Error::OutOfBounds));
+    }
+
+    #[test]
+    fn validate_sector_address_rejects_sector_whose_tail_overruns_flash() {
+        let flash_end = super::super::FLASH_BASE as u32 + super::super::FLASH_SIZE as u32;
+        let sector = FlashSector {
+            bank: FlashBank::Bank2,
+            index_in_bank: 0,
+            start: flash_end - WRITE_SIZE as u32,
+            size: 2 * WRITE_SIZE as u32,
+        };
+        assert_eq!(validate_sector_address(&sector), Err(Error::OutOfBounds));
+    }
+
+    #[test]
+    fn validate_range_rejects_overflowing_address() {
+        // Aligned to WRITE_SIZE, but start_address + len would wrap past u32::MAX.
+        let address = u32::MAX - (u32::MAX % WRITE_SIZE as u32);
+        assert_eq!(validate_range(address, WRITE_SIZE as u32), Err(Error::OutOfBounds));
+    }
+}
+
+pub(crate) fn begin_erase_sector(sector: &FlashSector) -> Result<(), Error> {
+    validate_sector_address(sector)?;
+
+    // See the comment in `enable_write` - clear any stale ECC flag before
+    // this erase starts polling `status()`.
+    clear_ecc_err();
+
+    let bker = resolve_bker(sector.bank);
+
     #[cfg(feature = "trustzone-secure")]
     pac::FLASH.seccr().modify(|w| {
         w.set_per(pac::flash::vals::SeccrPer::B_0X1);
         w.set_pnb(sector.index_in_bank);
-        // TODO: add check for bank swap
-        w.set_bker(match sector.bank {
-            FlashBank::Bank1 => false,
-            FlashBank::Bank2 => true,
-            _ => unreachable!(),
-        });
+        w.set_bker(bker);
     });
     #[cfg(not(feature = "trustzone-secure"))]
     pac::FLASH.nscr().modify(|w| {
         w.set_per(true);
         w.set_pnb(sector.index_in_bank);
-        // TODO: add check for bank swap
-        w.set_bker(match sector.bank {
-            FlashBank::Bank1 => false,
-            FlashBank::Bank2 => true,
-            _ => unreachable!(),
-        });
+        w.set_bker(bker);
     });
 
     #[cfg(feature = "trustzone-secure")]
@@ -95,6 +356,8 @@ pub(crate) fn begin_erase_sector(sector: &FlashSector) {
     pac::FLASH.nscr().modify(|w| {
         w.set_strt(true);
     });
+
+    Ok(())
 }
 
 pub(crate) fn end_erase() -> Result<(), Error> {
@@ -117,7 +380,7 @@ pub(crate) fn end_erase() -> Result<(), Error> {
 }
 
 pub(crate) fn blocking_erase_sector(sector: &FlashSector) -> Result<(), Error> {
-    begin_erase_sector(sector);
+    begin_erase_sector(sector)?;
     // We discard this Result because we regenerate it in end_erase anyway.
     let _ = blocking_wait_ready();
     end_erase()
@@ -162,6 +425,41 @@ pub(crate) fn clear_all_err() {
     pac::FLASH.secsr().modify(|_| {});
     #[cfg(not(feature = "trustzone-secure"))]
     pac::FLASH.nssr().modify(|_| {});
+
+    clear_ecc_err();
+}
+
+fn clear_ecc_err() {
+    // Same trick as above: ECCC/ECCD are write-1-to-clear.
+    #[cfg(feature = "trustzone-secure")]
+    pac::FLASH.sececcr().modify(|_| {});
+    #[cfg(not(feature = "trustzone-secure"))]
+    pac::FLASH.nseccr().modify(|_| {});
+}
+
+/// The bank and word address that triggered the most recent ECC fault, as
+/// reported by the `ADDR_ECC`/`BK_ECC` fields of the ECC status register.
+pub struct EccFault {
+    pub bank: FlashBank,
+    pub address: u32,
+}
+
+/// Reads the location of the word that tripped the last ECC error.
+///
+/// Only meaningful right after [`status`] (or [`blocking_wait_ready`])
+/// returned [`Error::EccCorrected`] or [`Error::EccUncorrected`]; the
+/// register is shared with normal operation and gets overwritten by the
+/// next flash access that hits ECC-protected data.
+pub fn ecc_fault_address() -> EccFault {
+    #[cfg(feature = "trustzone-secure")]
+    let eccr = pac::FLASH.sececcr().read();
+    #[cfg(not(feature = "trustzone-secure"))]
+    let eccr = pac::FLASH.nseccr().read();
+
+    EccFault {
+        bank: if eccr.bk_ecc() { FlashBank::Bank2 } else { FlashBank::Bank1 },
+        address: eccr.addr_ecc() as u32 * 4,
+    }
 }
 
 pub(crate) fn status() -> Result<bool, Error> {
@@ -196,6 +494,23 @@ pub(crate) fn status() -> Result<bool, Error> {
             return Err(Error::Prog);
         }
 
+        // The ECC subsystem lives in its own status register, separate from
+        // the error flags above: a double-bit error is uncorrectable and
+        // otherwise escalates straight to an NMI, while a single-bit error
+        // was silently corrected by hardware but is still worth surfacing.
+        #[cfg(feature = "trustzone-secure")]
+        let eccr = pac::FLASH.sececcr().read();
+        #[cfg(not(feature = "trustzone-secure"))]
+        let eccr = pac::FLASH.nseccr().read();
+
+        if eccr.eccd() {
+            return Err(Error::EccUncorrected);
+        }
+
+        if eccr.eccc() {
+            return Err(Error::EccCorrected);
+        }
+
         // If there was no error, happy days - just return idle
         Ok(false)
     }
@@ -208,3 +523,158 @@ fn blocking_wait_ready() -> Result<(), Error> {
         }
     )
 }
+
+fn unlock_options() {
+    if pac::FLASH.nscr().read().optlock() {
+        pac::FLASH.optkeyr().write_value(0x0819_2A3B);
+        pac::FLASH.optkeyr().write_value(0x4C5D_6E7F);
+    }
+}
+
+fn lock_options() {
+    pac::FLASH.nscr().modify(|w| w.set_optlock(true));
+}
+
+/// Read-protection level applied to the whole device through the option bytes.
+///
+/// Level 2 (full, irreversible protection) is deliberately not a variant
+/// here - reach it only through [`set_rdp_level_2_irreversible`], so the
+/// irreversible transition can't be triggered via a regular
+/// [`OptionBytes::rdp_level`] call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RdpLevel {
+    /// No read protection.
+    Level0,
+    /// Debug access (JTAG/SWD) and boot from RAM/system memory are disabled
+    /// until RDP is lowered back to [`RdpLevel::Level0`].
+    Level1,
+}
+
+impl RdpLevel {
+    fn to_bits(self) -> u8 {
+        match self {
+            RdpLevel::Level0 => 0xAA,
+            RdpLevel::Level1 => 0x00,
+        }
+    }
+}
+
+const RDP_LEVEL_2_BITS: u8 = 0xCC;
+
+/// A pending set of option byte changes, applied atomically by [`OptionBytes::program`].
+///
+/// Build one with [`OptionBytes::new`], chain the setters for whatever needs
+/// changing, then call [`OptionBytes::program`] to unlock the option
+/// register, write the new values, and reload them with `OBL_LAUNCH`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OptionBytes {
+    rdp: Option<RdpLevel>,
+    rdp_level_2: bool,
+    bank1_wrp: Option<(u8, u8)>,
+    bank2_wrp: Option<(u8, u8)>,
+}
+
+impl OptionBytes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests a new RDP level.
+    ///
+    /// `RdpLevel` has no level-2 variant, so this can never trigger the
+    /// irreversible transition by accident - use
+    /// [`set_rdp_level_2_irreversible`] for that.
+    pub fn rdp_level(mut self, level: RdpLevel) -> Self {
+        self.rdp = Some(level);
+        self
+    }
+
+    /// Only reachable from within this module - see [`set_rdp_level_2_irreversible`].
+    fn rdp_level_2_irreversible(mut self) -> Self {
+        self.rdp_level_2 = true;
+        self
+    }
+
+    /// Write-protects pages `start_page..=end_page` of bank 1.
+    pub fn write_protect_bank1(mut self, start_page: u8, end_page: u8) -> Self {
+        self.bank1_wrp = Some((start_page, end_page));
+        self
+    }
+
+    /// Write-protects pages `start_page..=end_page` of bank 2.
+    pub fn write_protect_bank2(mut self, start_page: u8, end_page: u8) -> Self {
+        self.bank2_wrp = Some((start_page, end_page));
+        self
+    }
+
+    /// Unlocks the option register, applies the requested changes, and
+    /// triggers `OBL_LAUNCH` so they take effect immediately.
+    ///
+    /// Returns [`Error::Protected`] if the current write-protection or RDP
+    /// state forbids the requested change (e.g. lowering RDP below the
+    /// level already in force), or if the controller rejects the option-byte
+    /// commit itself (`OPTWERR`).
+    pub fn program(self) -> Result<(), Error> {
+        unlock_options();
+        // Always re-lock before returning, on every path - an early return
+        // here (e.g. on `Error::Protected`/`Error::Busy`) would otherwise
+        // leave OPTLOCK cleared indefinitely.
+        let result = self.program_unlocked();
+        lock_options();
+        result
+    }
+
+    fn program_unlocked(self) -> Result<(), Error> {
+        pac::FLASH.optr().modify(|w| {
+            if self.rdp_level_2 {
+                w.set_rdp(RDP_LEVEL_2_BITS);
+            } else if let Some(level) = self.rdp {
+                w.set_rdp(level.to_bits());
+            }
+        });
+
+        if let Some((start, end)) = self.bank1_wrp {
+            pac::FLASH.wrp1ar().modify(|w| {
+                w.set_wrp1a_strt(start);
+                w.set_wrp1a_end(end);
+            });
+        }
+
+        if let Some((start, end)) = self.bank2_wrp {
+            pac::FLASH.wrp2ar().modify(|w| {
+                w.set_wrp2a_strt(start);
+                w.set_wrp2a_end(end);
+            });
+        }
+
+        pac::FLASH.nscr().modify(|w| w.set_optstrt(true));
+        blocking_wait_ready()?;
+
+        // OPTSTRT can complete clean on BSY/the usual program-error flags
+        // while the controller still rejected the commit outright - OPTWERR
+        // is the dedicated flag for that, and nothing else surfaces it.
+        #[cfg(feature = "trustzone-secure")]
+        let rejected = pac::FLASH.secsr().read().optwerr();
+        #[cfg(not(feature = "trustzone-secure"))]
+        let rejected = pac::FLASH.nssr().read().optwerr();
+
+        if rejected {
+            return Err(Error::Protected);
+        }
+
+        // Reload the option bytes we just wrote. On success this resets the
+        // MCU, so nothing after this call is expected to run.
+        pac::FLASH.nscr().modify(|w| w.set_obl_launch(true));
+
+        Ok(())
+    }
+}
+
+/// Sets RDP to level 2, permanently disabling debug access.
+///
+/// This is irreversible - there is no option byte sequence that takes a
+/// chip back from level 2 to level 1 or 0. Only use this on firmware you
+/// are certain is final and production-ready.
+pub fn set_rdp_level_2_irreversible() -> Result<(), Error> {
+    OptionBytes::new().rdp_level_2_irreversible().program()
+}